@@ -0,0 +1,111 @@
+//! Reads `instructions.in` and emits `$OUT_DIR/opcodes.rs`, the single
+//! source of truth for the `OpCode` enum, `OpCode::from_u8`, and
+//! `OpCode::operand_len` -- see `instructions.in` for the table format.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+struct Instruction {
+    name: String,
+    operand_len: usize,
+}
+
+fn parse_instructions(table: &str) -> Vec<Instruction> {
+    table
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields
+                .next()
+                .unwrap_or_else(|| panic!("instructions.in: missing name in `{line}`"))
+                .to_string();
+            let operand_len = fields.next().unwrap_or("").len();
+            Instruction { name, operand_len }
+        })
+        .collect()
+}
+
+/// `ConstantLong` -> `CONSTANT_LONG`, for the `OP_`-prefixed display name.
+fn screaming_snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (idx, ch) in name.chars().enumerate() {
+        if ch.is_uppercase() && idx != 0 {
+            out.push('_');
+        }
+        out.extend(ch.to_uppercase());
+    }
+    out
+}
+
+fn generate(instructions: &[Instruction]) -> String {
+    let mut out = String::new();
+
+    out.push_str("#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]\n");
+    out.push_str("#[repr(u8)]\n");
+    out.push_str("pub enum OpCode {\n");
+    for (discriminant, instruction) in instructions.iter().enumerate() {
+        let _ = writeln!(out, "    {} = {discriminant},", instruction.name);
+    }
+    out.push_str("}\n\n");
+
+    out.push_str("impl OpCode {\n");
+    out.push_str("    #[inline]\n");
+    out.push_str("    pub fn from_u8(value: u8) -> Option<Self> {\n");
+    out.push_str("        match value {\n");
+    for (discriminant, instruction) in instructions.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "            {discriminant} => Some(OpCode::{}),",
+            instruction.name
+        );
+    }
+    out.push_str("            _ => None,\n");
+    out.push_str("        }\n");
+    out.push_str("    }\n\n");
+
+    out.push_str("    #[inline]\n");
+    out.push_str("    pub fn operand_len(self) -> usize {\n");
+    out.push_str("        match self {\n");
+    for instruction in instructions {
+        let _ = writeln!(
+            out,
+            "            OpCode::{} => {},",
+            instruction.name, instruction.operand_len
+        );
+    }
+    out.push_str("        }\n");
+    out.push_str("    }\n\n");
+
+    out.push_str("    #[inline]\n");
+    out.push_str("    pub fn name(self) -> &'static str {\n");
+    out.push_str("        match self {\n");
+    for instruction in instructions {
+        let _ = writeln!(
+            out,
+            "            OpCode::{} => \"OP_{}\",",
+            instruction.name,
+            screaming_snake_case(&instruction.name)
+        );
+    }
+    out.push_str("        }\n");
+    out.push_str("    }\n");
+    out.push_str("}\n");
+
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=instructions.in");
+
+    let table = fs::read_to_string("instructions.in").expect("failed to read instructions.in");
+    let instructions = parse_instructions(&table);
+    let generated = generate(&instructions);
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+    let dest = Path::new(&out_dir).join("opcodes.rs");
+    fs::write(dest, generated).expect("failed to write opcodes.rs");
+}
@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Write as _};
 
 use crate::value::Value;
 use thiserror::Error;
@@ -13,40 +13,18 @@ pub enum ChunkError {
     TooManyConstantsLong,
     #[error("Offset `{0}` not associated with any line")]
     ParseLineForOffset(usize),
+    #[error("Instruction at offset `{0}` is missing operand bytes")]
+    TruncatedInstruction(usize),
+    #[error("Failed to write disassembly: {0}")]
+    Format(#[from] fmt::Error),
 }
 
 pub type ChunkResult<T> = Result<T, ChunkError>;
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-#[repr(u8)]
-pub enum OpCode {
-    Constant = 0,
-    ConstantLong,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Negate,
-    Return,
-}
-
-impl OpCode {
-    #[inline]
-    pub fn from_u8(value: u8) -> Option<Self> {
-        use OpCode::*;
-        match value {
-            0 => Some(Constant),
-            1 => Some(ConstantLong),
-            2 => Some(Add),
-            3 => Some(Subtract),
-            4 => Some(Multiply),
-            5 => Some(Divide),
-            6 => Some(Negate),
-            7 => Some(Return),
-            _ => None,
-        }
-    }
-}
+// Generated by build.rs from `instructions.in`: the `OpCode` enum, `from_u8`,
+// `operand_len`, and `name` all come from that single table so they can't
+// drift out of sync with one another.
+include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
 
 #[derive(Debug, Copy, Clone)]
 pub struct Rle<T: Debug + Copy> {
@@ -89,17 +67,20 @@ impl Chunk {
         }
     }
 
-    pub fn write_constant(&mut self, value: Value, line: usize) -> ChunkResult<()> {
+    /// Writes an instruction loading `value` into register `dst`: `Constant`
+    /// while the pool holds fewer than 255 constants, `ConstantLong` after.
+    pub fn write_constant(&mut self, dst: u8, value: Value, line: usize) -> ChunkResult<()> {
         if self.constants.len() < u8::MAX as usize {
-            self.write_constant_short(value, line)
+            self.write_constant_short(dst, value, line)
         } else {
-            self.write_constant_long(value, line)
+            self.write_constant_long(dst, value, line)
         }
     }
 
-    fn write_constant_short(&mut self, value: Value, line: usize) -> ChunkResult<()> {
+    fn write_constant_short(&mut self, dst: u8, value: Value, line: usize) -> ChunkResult<()> {
         let constant_idx = self.add_constant(value);
         self.write_byte(OpCode::Constant as u8, line);
+        self.write_byte(dst, line);
         let trunc_idx = constant_idx
             .try_into()
             .map_err(|_| ChunkError::TooManyConstantsShort)?;
@@ -107,9 +88,10 @@ impl Chunk {
         Ok(())
     }
 
-    fn write_constant_long(&mut self, value: Value, line: usize) -> ChunkResult<()> {
+    fn write_constant_long(&mut self, dst: u8, value: Value, line: usize) -> ChunkResult<()> {
         let constant_idx = self.add_constant(value);
         self.write_byte(OpCode::ConstantLong as u8, line);
+        self.write_byte(dst, line);
         let [b1, b2] = TryInto::<u16>::try_into(constant_idx)
             .map_err(|_| ChunkError::TooManyConstantsLong)?
             .to_le_bytes();
@@ -134,61 +116,128 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn disassemble(&self, name: &str) -> ChunkResult<()> {
-        println!("== {name} ==");
+    /// Renders a full listing of the chunk, suitable for printing, embedding
+    /// in an error report, or asserting on in a test.
+    pub fn disassemble(&self, name: &str) -> ChunkResult<String> {
+        let mut out = String::new();
+        writeln!(out, "== {name} ==")?;
+        writeln!(out, "{:<6} {:<4} INSTRUCTION", "OFFSET", "LINE")?;
         let mut offset = 0;
         while offset < self.len() {
-            offset = self.disassemble_instruction(offset)?;
+            offset = self.disassemble_instruction(&mut out, offset)?;
         }
-        Ok(())
+        Ok(out)
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> ChunkResult<usize> {
-        print!("{offset:04} ");
+    /// Writes a single disassembled instruction at `offset` to `out` and
+    /// returns the offset of the next instruction.
+    pub fn disassemble_instruction(
+        &self,
+        out: &mut impl fmt::Write,
+        offset: usize,
+    ) -> ChunkResult<usize> {
+        write!(out, "{offset:04}   ")?;
         let line = self.get_line(offset)?;
         if offset > 0 && line == self.get_line(offset - 1)? {
-            print!("   | ");
+            write!(out, "|    ")?;
         } else {
-            print!("{line:4} ");
+            write!(out, "{line:<4} ")?;
         }
-        if let Some(instruction) = OpCode::from_u8(self.code[offset]) {
-            match instruction {
-                OpCode::Constant => Ok(self.constant_instruction("OP_CONSTANT", offset)),
-                OpCode::ConstantLong => {
-                    Ok(self.constant_long_instruction("OP_CONSTANT_LONG", offset))
-                }
-                OpCode::Add => Ok(Self::simple_instruction("OP_ADD", offset)),
-                OpCode::Subtract => Ok(Self::simple_instruction("OP_SUBTRACT", offset)),
-                OpCode::Multiply => Ok(Self::simple_instruction("OP_MULTIPLY", offset)),
-                OpCode::Divide => Ok(Self::simple_instruction("OP_DIVIDE", offset)),
-                OpCode::Negate => Ok(Self::simple_instruction("OP_NEGATE", offset)),
-                OpCode::Return => Ok(Self::simple_instruction("OP_RETURN", offset)),
-            }
-        } else {
-            Err(ChunkError::ParseOpCode(self.code[offset]))
+        let Some(instruction) = OpCode::from_u8(self.code[offset]) else {
+            return Err(ChunkError::ParseOpCode(self.code[offset]));
+        };
+        let len = instruction.operand_len();
+        if offset + 1 + len > self.len() {
+            return Err(ChunkError::TruncatedInstruction(offset));
         }
+        let operands = &self.code[offset + 1..offset + 1 + len];
+        self.write_operands(out, instruction, operands)?;
+        Ok(offset + 1 + len)
+    }
+
+    /// Generic operand printer driven by `OpCode::operand_len`, so adding an
+    /// instruction to `instructions.in` is enough to disassemble it.
+    fn write_operands(
+        &self,
+        out: &mut impl fmt::Write,
+        instruction: OpCode,
+        operands: &[u8],
+    ) -> ChunkResult<()> {
+        write!(out, "{:-16}", instruction.name())?;
+        for operand in operands {
+            write!(out, " {operand:4}")?;
+        }
+        if let Some(constant_idx) = Self::constant_operand_index(instruction, operands) {
+            write!(out, " '{}'", self.constants[constant_idx])?;
+        }
+        writeln!(out)?;
+        Ok(())
     }
 
-    fn simple_instruction(name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+    fn constant_operand_index(instruction: OpCode, operands: &[u8]) -> Option<usize> {
+        match instruction {
+            OpCode::Constant => Some(operands[1] as usize),
+            OpCode::ConstantLong => Some(u16::from_le_bytes([operands[1], operands[2]]) as usize),
+            _ => None,
+        }
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_idx = self.code[offset + 1];
-        print!("{name:-16} {constant_idx:4} '");
-        print!("{}", self.constants[constant_idx as usize]);
-        println!("'");
-        offset + 2
+    /// Number of registers this chunk touches, so a `Vm` can size its
+    /// register file to the chunk instead of carrying a fixed-size array.
+    /// Every instruction's first operand byte is a register index, except
+    /// `Constant`/`ConstantLong`, whose trailing bytes are a constant-pool
+    /// index rather than a register.
+    pub fn register_count(&self) -> usize {
+        let mut count = 0;
+        let mut offset = 0;
+        while offset < self.len() {
+            let Some(instruction) = OpCode::from_u8(self.code[offset]) else {
+                break;
+            };
+            let len = instruction.operand_len();
+            if offset + 1 + len > self.len() {
+                break;
+            }
+            for &reg in Self::register_operands(instruction, &self.code[offset + 1..offset + 1 + len]) {
+                count = count.max(reg as usize + 1);
+            }
+            offset += 1 + len;
+        }
+        count
     }
 
-    fn constant_long_instruction(&self, name: &str, offset: usize) -> usize {
-        let b1 = self.code[offset + 1];
-        let b2 = self.code[offset + 2];
-        let constant_idx = u16::from_le_bytes([b1, b2]);
-        print!("{name:-16} {constant_idx:4} '");
-        print!("{}", self.constants[constant_idx as usize]);
-        println!("'");
-        offset + 3
+    /// The subset of `operands` that are register indices rather than, e.g.,
+    /// a constant-pool index.
+    fn register_operands(instruction: OpCode, operands: &[u8]) -> &[u8] {
+        match instruction {
+            OpCode::Constant | OpCode::ConstantLong => &operands[..1],
+            _ => operands,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn disassemble_known_chunk_is_byte_for_byte_stable() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(0, Value::Number(1.5), 7).unwrap();
+        chunk.write_byte(OpCode::Negate as u8, 7);
+        chunk.write_byte(1, 7); // dst
+        chunk.write_byte(0, 7); // src
+        chunk.write_byte(OpCode::Return as u8, 7);
+        chunk.write_byte(1, 7); // src
+
+        let listing = chunk.disassemble("known").unwrap();
+
+        let expected = "== known ==\n\
+            OFFSET LINE INSTRUCTION\n\
+            0000   7    OP_CONSTANT         0    0 '1.5'\n\
+            0003   |    OP_NEGATE           1    0\n\
+            0006   |    OP_RETURN           1\n";
+        assert_eq!(listing, expected);
     }
 }
@@ -3,6 +3,7 @@ mod value;
 mod vm;
 
 use chunk::{Chunk, OpCode};
+use value::Value;
 use vm::Vm;
 
 use anyhow::Result;
@@ -10,19 +11,30 @@ use anyhow::Result;
 fn main() -> Result<()> {
     // Prepare the chunk.
     let mut chunk = Chunk::new();
-    chunk.write_constant(1.2, 123)?;
-    chunk.write_constant(3.4, 123)?;
+    chunk.write_constant(0, Value::Number(1.2), 123)?;
+    chunk.write_constant(1, Value::Number(3.4), 123)?;
     chunk.write_byte(OpCode::Add as u8, 123);
-    chunk.write_constant(5.6, 123)?;
+    chunk.write_byte(2, 123); // dst
+    chunk.write_byte(0, 123); // src1
+    chunk.write_byte(1, 123); // src2
+    chunk.write_constant(3, Value::Number(5.6), 123)?;
     chunk.write_byte(OpCode::Divide as u8, 123);
+    chunk.write_byte(4, 123); // dst
+    chunk.write_byte(2, 123); // src1
+    chunk.write_byte(3, 123); // src2
     chunk.write_byte(OpCode::Negate as u8, 123);
+    chunk.write_byte(5, 123); // dst
+    chunk.write_byte(4, 123); // src
     chunk.write_byte(OpCode::Return as u8, 123);
+    chunk.write_byte(5, 123); // src
 
     // Disassemble the chunk for review.
-    chunk.disassemble("test chunk")?;
+    print!("{}", chunk.disassemble("test chunk")?);
 
-    // Run the chunk in the VM.
-    let mut vm = Vm::new(chunk);
+    // Run the chunk in the VM, capped well above anything this hand-written
+    // program could need so a future malformed/runaway chunk traps instead
+    // of spinning forever.
+    let mut vm = Vm::with_limit(chunk, 1_000_000);
     vm.run()?;
 
     Ok(())
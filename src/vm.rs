@@ -1,24 +1,62 @@
-use crate::chunk::{Chunk, OpCode};
+use std::fmt;
+
+use crate::chunk::{Chunk, ChunkError, OpCode};
 use crate::value::Value;
 
 use thiserror::Error;
 
-const STACK_MAX: usize = 256;
+/// Distinguishable failure modes a malformed or runaway chunk can trigger,
+/// so callers don't have to pattern-match on `InterpretError::Runtime`'s
+/// string payload to tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// The instruction budget passed to `Vm::with_limit` hit zero before the
+    /// chunk returned.
+    BudgetExhausted,
+    /// `OpCode::from_u8` didn't recognize the byte at the instruction pointer.
+    BadOpcode(u8),
+    /// The instruction pointer ran past the end of the chunk mid-instruction,
+    /// e.g. an opcode whose trailing operand bytes were never written.
+    TruncatedInstruction,
+    /// A register operand named a register beyond the end of the `Vm`'s
+    /// register file. `Vm::new` sizes that file with `Chunk::register_count`,
+    /// so this only fires when the register file is smaller than what the
+    /// chunk references.
+    RegisterOutOfBounds(u8),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::BudgetExhausted => write!(f, "instruction budget exhausted"),
+            Trap::BadOpcode(byte) => write!(f, "unrecognized opcode `{byte}`"),
+            Trap::TruncatedInstruction => write!(f, "instruction truncated at end of chunk"),
+            Trap::RegisterOutOfBounds(reg) => write!(f, "register `r{reg}` out of bounds"),
+        }
+    }
+}
 
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {{
-        let b = $self.pop();
-        // Don't explicitly pop `a` off -- update in place.
-        unsafe { *($self.stack_top.sub(1)) = *($self.stack_top.sub(1)) $op b };
+    ($self:ident, $op:tt, $wrap:expr) => {{
+        let dst = $self.read_byte()?;
+        let src1 = $self.read_byte()?;
+        let src2 = $self.read_byte()?;
+        match ($self.register(src1)?, $self.register(src2)?) {
+            (Value::Number(a), Value::Number(b)) => {
+                $self.set_register(dst, $wrap(a $op b))?;
+                Ok(())
+            }
+            _ => Err(InterpretError::Runtime("Operands must be numbers.".to_string())),
+        }
     }}
 }
 
 #[derive(Error, Debug)]
 pub enum InterpretError {
-    #[error("Compilation error: {0}")]
-    Compilation(String),
     #[error("Runtime error: {0}")]
     Runtime(String),
+    #[error("{0}")]
+    Trap(Trap),
 }
 
 pub type InterpretResult<T> = Result<T, InterpretError>;
@@ -26,107 +64,332 @@ pub type InterpretResult<T> = Result<T, InterpretError>;
 pub struct Vm {
     chunk: Chunk,
     ip: *mut u8,
-    stack: [Value; STACK_MAX],
-    stack_top: *mut Value,
+    // Sized to `chunk.register_count()`, not a fixed maximum, so a small
+    // chunk doesn't pay for (or trace) registers it never touches.
+    registers: Vec<Value>,
+    /// Remaining instructions this `Vm` is allowed to execute, or `None` for
+    /// no limit. Set via `Vm::with_limit`.
+    budget: Option<usize>,
 }
 
 impl Vm {
     pub fn new(chunk: Chunk) -> Self {
-        let mut vm = Self {
+        let registers = vec![Value::Nil; chunk.register_count()];
+        // `Vec::as_ptr` never indexes, so this stays valid even for an empty
+        // chunk -- unlike `&chunk.code[0]`, it can't panic on construction.
+        // `read_byte` bounds-checks before ever dereferencing `ip`.
+        let ip = chunk.code.as_ptr() as *mut u8;
+        Self {
             chunk,
-            ip: std::ptr::null::<*const u8>() as *mut u8,
-            stack: [0.0; STACK_MAX],
-            stack_top: std::ptr::null::<*const Value> as *mut Value,
-        };
-        vm.ip = std::ptr::addr_of!(vm.chunk.code[0]) as *mut u8;
-        vm.stack_top = std::ptr::addr_of!(vm.stack[0]) as *mut Value;
-        vm
-    }
-
-    pub fn reset_stack(&mut self) {
-        self.stack_top = std::ptr::addr_of!(self.stack[0]) as *mut Value;
-    }
-
-    pub fn push(&mut self, value: Value) {
-        unsafe {
-            *self.stack_top = value;
-            self.stack_top = self.stack_top.add(1);
-        };
+            ip,
+            registers,
+            budget: None,
+        }
     }
 
-    pub fn pop(&mut self) -> Value {
-        unsafe {
-            self.stack_top = self.stack_top.sub(1);
-            *self.stack_top
+    /// Like `Vm::new`, but `run` returns `InterpretError::Trap(Trap::BudgetExhausted)`
+    /// once `max_instructions` have executed, guarding against a malformed
+    /// chunk that loops forever.
+    pub fn with_limit(chunk: Chunk, max_instructions: usize) -> Self {
+        Self {
+            budget: Some(max_instructions),
+            ..Self::new(chunk)
         }
     }
 
     pub fn run(&mut self) -> InterpretResult<()> {
         use OpCode::*;
         loop {
+            if let Some(budget) = &mut self.budget {
+                if *budget == 0 {
+                    return Err(InterpretError::Trap(Trap::BudgetExhausted));
+                }
+                *budget -= 1;
+            }
+
+            // Nothing left to trace or dispatch -- an empty chunk, or the
+            // instruction pointer having walked off the end of one, hits
+            // this before the debug trace below can try to disassemble
+            // past the chunk's bounds.
+            if self.offset() >= self.chunk.len() {
+                return Err(InterpretError::Trap(Trap::TruncatedInstruction));
+            }
+
             #[cfg(debug_assertions)]
             {
                 print!("          ");
-                let stack_top_offset = unsafe {
-                    self.stack_top
-                        .offset_from(std::ptr::addr_of!(self.stack[0]))
-                } as usize;
-                for slot in &self.stack[0..stack_top_offset] {
-                    print!("[ {slot} ]");
+                for (idx, slot) in self.registers.iter().enumerate() {
+                    print!("[r{idx}: {slot}]");
                 }
                 println!();
-                let offset =
-                    unsafe { self.ip.offset_from(std::ptr::addr_of!(self.chunk.code[0])) } as usize;
+                let offset = self.offset();
+                let mut trace = String::new();
                 self.chunk
-                    .disassemble_instruction(offset)
-                    .map_err(|e| InterpretError::Runtime(e.to_string()))?;
+                    .disassemble_instruction(&mut trace, offset)
+                    .map_err(|e| match e {
+                        ChunkError::ParseOpCode(byte) => InterpretError::Trap(Trap::BadOpcode(byte)),
+                        ChunkError::TruncatedInstruction(_) => {
+                            InterpretError::Trap(Trap::TruncatedInstruction)
+                        }
+                        other => InterpretError::Runtime(other.to_string()),
+                    })?;
+                print!("{trace}");
             }
 
-            let byte = unsafe { self.read_byte() };
+            let byte = self.read_byte()?;
             match OpCode::from_u8(byte) {
                 Some(Constant) => {
-                    let constant = self.read_constant();
-                    self.push(constant);
+                    let dst = self.read_byte()?;
+                    let constant = self.read_constant()?;
+                    self.set_register(dst, constant)?;
                 }
                 Some(ConstantLong) => {
-                    let constant = self.read_constant_long();
-                    self.push(constant);
+                    let dst = self.read_byte()?;
+                    let constant = self.read_constant_long()?;
+                    self.set_register(dst, constant)?;
                 }
                 Some(Negate) => {
-                    unsafe { *(self.stack_top.sub(1)) = -*(self.stack_top.sub(1)) };
+                    let dst = self.read_byte()?;
+                    let src = self.read_byte()?;
+                    match self.register(src)? {
+                        Value::Number(n) => self.set_register(dst, Value::Number(-n))?,
+                        _ => {
+                            return Err(InterpretError::Runtime(
+                                "Operand must be a number.".to_string(),
+                            ));
+                        }
+                    }
+                }
+                Some(Add) => binary_op!(self, +, Value::Number)?,
+                Some(Subtract) => binary_op!(self, -, Value::Number)?,
+                Some(Multiply) => binary_op!(self, *, Value::Number)?,
+                Some(Divide) => binary_op!(self, /, Value::Number)?,
+                Some(Greater) => binary_op!(self, >, Value::Bool)?,
+                Some(Less) => binary_op!(self, <, Value::Bool)?,
+                Some(Equal) => {
+                    let dst = self.read_byte()?;
+                    let src1 = self.read_byte()?;
+                    let src2 = self.read_byte()?;
+                    let equal = self.register(src1)? == self.register(src2)?;
+                    self.set_register(dst, Value::Bool(equal))?;
+                }
+                Some(Not) => {
+                    let dst = self.read_byte()?;
+                    let src = self.read_byte()?;
+                    let falsy = self.register(src)?.is_falsy();
+                    self.set_register(dst, Value::Bool(falsy))?;
+                }
+                Some(True) => {
+                    let dst = self.read_byte()?;
+                    self.set_register(dst, Value::Bool(true))?;
+                }
+                Some(False) => {
+                    let dst = self.read_byte()?;
+                    self.set_register(dst, Value::Bool(false))?;
+                }
+                Some(Nil) => {
+                    let dst = self.read_byte()?;
+                    self.set_register(dst, Value::Nil)?;
                 }
-                Some(Add) => binary_op!(self, +),
-                Some(Subtract) => binary_op!(self, -),
-                Some(Multiply) => binary_op!(self, *),
-                Some(Divide) => binary_op!(self, /),
                 Some(Return) => {
-                    println!("{}", self.pop());
+                    let src = self.read_byte()?;
+                    println!("{}", self.register(src)?);
                     return Ok(());
                 }
-                None => {
-                    return Err(InterpretError::Runtime(format!(
-                        "Unsupported opcode: `{byte}`"
-                    )));
-                }
+                None => return Err(InterpretError::Trap(Trap::BadOpcode(byte))),
             }
         }
     }
 
+    /// Offset of the instruction pointer into `self.chunk`'s code, used to
+    /// bounds-check `read_byte` against the chunk's actual length.
+    fn offset(&self) -> usize {
+        unsafe { self.ip.offset_from(self.chunk.code.as_ptr()) as usize }
+    }
+
     #[inline]
-    unsafe fn read_byte(&mut self) -> u8 {
-        let byte = *self.ip;
-        self.ip = self.ip.add(1);
-        byte
+    fn read_byte(&mut self) -> InterpretResult<u8> {
+        if self.offset() >= self.chunk.len() {
+            return Err(InterpretError::Trap(Trap::TruncatedInstruction));
+        }
+        let byte = unsafe { *self.ip };
+        self.ip = unsafe { self.ip.add(1) };
+        Ok(byte)
+    }
+
+    fn read_constant(&mut self) -> InterpretResult<Value> {
+        let constant_idx = self.read_byte()? as usize;
+        Ok(self.chunk.constants[constant_idx])
+    }
+
+    fn read_constant_long(&mut self) -> InterpretResult<Value> {
+        let b1 = self.read_byte()?;
+        let b2 = self.read_byte()?;
+        Ok(self.chunk.constants[u16::from_le_bytes([b1, b2]) as usize])
+    }
+
+    fn register(&self, reg: u8) -> InterpretResult<Value> {
+        self.registers
+            .get(reg as usize)
+            .copied()
+            .ok_or(InterpretError::Trap(Trap::RegisterOutOfBounds(reg)))
+    }
+
+    fn set_register(&mut self, reg: u8, value: Value) -> InterpretResult<()> {
+        match self.registers.get_mut(reg as usize) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(InterpretError::Trap(Trap::RegisterOutOfBounds(reg))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_program_matches_stack_equivalent() {
+        // Equivalent to the old stack-machine program:
+        //   push 1.2, push 3.4, add, push 5.6, divide, negate, return.
+        let mut chunk = Chunk::new();
+        chunk.write_constant(0, Value::Number(1.2), 1).unwrap();
+        chunk.write_constant(1, Value::Number(3.4), 1).unwrap();
+        chunk.write_byte(OpCode::Add as u8, 1);
+        chunk.write_byte(2, 1); // dst
+        chunk.write_byte(0, 1); // src1
+        chunk.write_byte(1, 1); // src2
+        chunk.write_constant(3, Value::Number(5.6), 1).unwrap();
+        chunk.write_byte(OpCode::Divide as u8, 1);
+        chunk.write_byte(4, 1); // dst
+        chunk.write_byte(2, 1); // src1
+        chunk.write_byte(3, 1); // src2
+        chunk.write_byte(OpCode::Negate as u8, 1);
+        chunk.write_byte(5, 1); // dst
+        chunk.write_byte(4, 1); // src
+        chunk.write_byte(OpCode::Return as u8, 1);
+        chunk.write_byte(5, 1); // src
+
+        let mut vm = Vm::new(chunk);
+        vm.run().unwrap();
+
+        let expected = Value::Number(-((1.2_f64 + 3.4) / 5.6));
+        assert_eq!(vm.registers[5], expected);
+    }
+
+    #[test]
+    fn equality_and_comparison_opcodes_produce_bools() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(0, Value::Number(1.0), 1).unwrap();
+        chunk.write_constant(1, Value::Number(2.0), 1).unwrap();
+        chunk.write_byte(OpCode::Less as u8, 1);
+        chunk.write_byte(2, 1); // dst
+        chunk.write_byte(0, 1); // src1
+        chunk.write_byte(1, 1); // src2
+        chunk.write_byte(OpCode::Not as u8, 1);
+        chunk.write_byte(3, 1); // dst
+        chunk.write_byte(2, 1); // src
+        chunk.write_byte(OpCode::Return as u8, 1);
+        chunk.write_byte(3, 1); // src
+
+        let mut vm = Vm::new(chunk);
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], Value::Bool(true));
+        assert_eq!(vm.registers[3], Value::Bool(false));
+    }
+
+    #[test]
+    fn arithmetic_on_non_numbers_is_a_runtime_error() {
+        let mut chunk = Chunk::new();
+        chunk.write_byte(OpCode::Nil as u8, 1);
+        chunk.write_byte(0, 1); // dst
+        chunk.write_constant(1, Value::Number(1.0), 1).unwrap();
+        chunk.write_byte(OpCode::Add as u8, 1);
+        chunk.write_byte(2, 1); // dst
+        chunk.write_byte(0, 1); // src1
+        chunk.write_byte(1, 1); // src2
+
+        let mut vm = Vm::new(chunk);
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, InterpretError::Runtime(_)));
+    }
+
+    #[test]
+    fn exhausted_budget_raises_a_trap() {
+        // Stands in for an infinite loop: jump-free chunks can't actually loop, so just
+        // pad the program past the budget with harmless no-op-ish writes.
+        let mut chunk = Chunk::new();
+        for _ in 0..4 {
+            chunk.write_constant(0, Value::Number(1.0), 1).unwrap();
+        }
+        chunk.write_byte(OpCode::Return as u8, 1);
+        chunk.write_byte(0, 1); // src
+
+        let mut vm = Vm::with_limit(chunk, 2);
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, InterpretError::Trap(Trap::BudgetExhausted)));
+    }
+
+    #[test]
+    fn empty_chunk_raises_a_trap_instead_of_panicking() {
+        let mut vm = Vm::new(Chunk::new());
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, InterpretError::Trap(Trap::TruncatedInstruction)));
     }
 
-    fn read_constant(&mut self) -> Value {
-        let constant_idx = unsafe { self.read_byte() } as usize;
-        self.chunk.constants[constant_idx]
+    #[test]
+    fn bad_opcode_raises_a_trap() {
+        let mut chunk = Chunk::new();
+        chunk.write_byte(0xff, 1);
+
+        let mut vm = Vm::new(chunk);
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, InterpretError::Trap(Trap::BadOpcode(0xff))));
+    }
+
+    #[test]
+    fn truncated_instruction_raises_a_trap() {
+        // `Add` needs three operand bytes (dst, src1, src2) that were never
+        // written, so the instruction pointer would run past the chunk.
+        let mut chunk = Chunk::new();
+        chunk.write_byte(OpCode::Add as u8, 1);
+
+        let mut vm = Vm::new(chunk);
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, InterpretError::Trap(Trap::TruncatedInstruction)));
     }
 
-    fn read_constant_long(&mut self) -> Value {
-        let b1 = unsafe { self.read_byte() };
-        let b2 = unsafe { self.read_byte() };
-        self.chunk.constants[u16::from_le_bytes([b1, b2]) as usize]
+    #[test]
+    fn register_count_is_sized_to_the_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(0, Value::Number(1.0), 1).unwrap();
+        chunk.write_byte(OpCode::Return as u8, 1);
+        chunk.write_byte(0, 1); // src
+
+        assert_eq!(chunk.register_count(), 1);
+    }
+
+    #[test]
+    fn register_out_of_bounds_raises_a_trap() {
+        // `Vm::new` always sizes `registers` to `chunk.register_count()`, so
+        // reaching this trap means building a `Vm` whose register file is
+        // smaller than what its chunk references -- exercise the bounds
+        // check directly rather than relying on `Vm::new` to misbehave.
+        let mut chunk = Chunk::new();
+        chunk.write_byte(OpCode::True as u8, 1);
+        chunk.write_byte(5, 1); // dst
+        chunk.write_byte(OpCode::Return as u8, 1);
+        chunk.write_byte(5, 1); // src
+
+        let mut vm = Vm::new(chunk);
+        vm.registers = vec![Value::Nil; 1];
+        let err = vm.run().unwrap_err();
+        assert!(matches!(
+            err,
+            InterpretError::Trap(Trap::RegisterOutOfBounds(5))
+        ));
     }
 }